@@ -1,16 +1,82 @@
 //! PUB-SUB auto-serializing structures.
 
+use std::collections::VecDeque;
 use std::marker::PhantomData;
 
 use jsonrpc_core as core;
 use jsonrpc_pubsub as pubsub;
+use rand::{self, Rng};
 use serde;
 use util::to_value;
 
-use self::core::futures::{self, Sink as FuturesSink};
+use self::core::futures::{self, Future, Sink as FuturesSink, Stream};
 
 pub use self::pubsub::SubscriptionId;
 
+/// Subscription ID generator.
+///
+/// Implementations hand out fresh `SubscriptionId`s so that servers don't
+/// have to invent (and often mis-manage) their own identifiers.
+pub trait IdProvider {
+	/// Returns a fresh subscription id.
+	fn next_id(&self) -> SubscriptionId;
+}
+
+/// Subscription id provider that yields random `u64` numbers.
+#[derive(Debug, Default)]
+pub struct RandomIntegerIdProvider;
+
+impl RandomIntegerIdProvider {
+	/// Creates a new `RandomIntegerIdProvider`.
+	pub fn new() -> Self {
+		Default::default()
+	}
+}
+
+impl IdProvider for RandomIntegerIdProvider {
+	fn next_id(&self) -> SubscriptionId {
+		SubscriptionId::Number(rand::thread_rng().gen())
+	}
+}
+
+/// Subscription id provider that yields random alphanumeric strings.
+#[derive(Debug)]
+pub struct RandomStringIdProvider {
+	len: usize,
+}
+
+impl RandomStringIdProvider {
+	/// Creates a new `RandomStringIdProvider` emitting 16-char ids.
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Creates a new `RandomStringIdProvider` emitting `len`-char ids.
+	pub fn with_len(len: usize) -> Self {
+		RandomStringIdProvider {
+			len: len,
+		}
+	}
+}
+
+impl Default for RandomStringIdProvider {
+	fn default() -> Self {
+		RandomStringIdProvider {
+			len: 16,
+		}
+	}
+}
+
+impl IdProvider for RandomStringIdProvider {
+	fn next_id(&self) -> SubscriptionId {
+		let mut rng = rand::thread_rng();
+		let id: String = (0..self.len)
+			.map(|_| rng.sample(rand::distributions::Alphanumeric))
+			.collect();
+		SubscriptionId::String(id)
+	}
+}
+
 /// New PUB-SUB subcriber.
 #[derive(Debug)]
 pub struct Subscriber<T> {
@@ -36,14 +102,102 @@ impl<T> Subscriber<T> {
 	/// This method consumes `Subscriber` and returns `Sink`
 	/// if the connection is still open or error otherwise.
 	pub fn assign_id(self, id: SubscriptionId) -> Result<Sink<T>, ()> {
+		self.assign_id_with_encoding(id, ParamEncoding::AsArrayElement)
+	}
+
+	/// Assign id to this subscriber, choosing how payloads are encoded.
+	///
+	/// Like `assign_id`, but the resulting `Sink` serializes notification
+	/// payloads according to `encoding`, letting servers emit structured
+	/// events as by-name params.
+	pub fn assign_id_with_encoding(self, id: SubscriptionId, encoding: ParamEncoding) -> Result<Sink<T>, ()> {
 		let sink = self.subscriber.assign_id(id.clone())?;
 		Ok(Sink {
 			id: id,
 			sink: sink,
-			buffered: None,
+			buffered: VecDeque::with_capacity(DEFAULT_CAPACITY),
+			capacity: DEFAULT_CAPACITY,
+			policy: OverflowPolicy::Block,
+			dropped: 0,
+			encoding: encoding,
+			close_notification: None,
+			close_reason: CloseReason::Unsubscribed,
+			closed_sent: false,
 			_data: PhantomData,
 		})
 	}
+
+	/// Assign an automatically generated id to this subscriber.
+	///
+	/// The id is obtained from the given `IdProvider`, which lets servers
+	/// hand out unguessable, non-colliding ids without bespoke bookkeeping.
+	/// Forwards to `assign_id` once an id has been drawn.
+	pub fn assign_auto_id(self, provider: &dyn IdProvider) -> Result<Sink<T>, ()> {
+		let id = provider.next_id();
+		self.assign_id(id)
+	}
+}
+
+/// Default number of notifications buffered per subscription.
+const DEFAULT_CAPACITY: usize = 1;
+
+/// Why a subscription was torn down.
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CloseReason {
+	/// The client unsubscribed or the subscription ended normally.
+	Unsubscribed,
+	/// The server is shutting down.
+	ServerShutdown,
+	/// A per-subscription limit (e.g. the buffer overflow policy) kicked in.
+	LimitExceeded,
+	/// The subscription failed for the given reason.
+	Failed(String),
+}
+
+/// Final notification payload describing why a subscription went away.
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize)]
+pub struct SubscriptionClosed {
+	/// Id of the subscription that was closed.
+	pub id: SubscriptionId,
+	/// Reason the subscription was closed.
+	pub reason: CloseReason,
+}
+
+/// How a typed notification payload is encoded into JSON-RPC params.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamEncoding {
+	/// Wrap the serialized value as the single element of a positional array.
+	AsArrayElement,
+	/// Spread a serialized sequence into the positional array, falling back
+	/// to a single-element array when the value is not a sequence.
+	AsArray,
+	/// Emit the serialized value as by-name params, falling back to a
+	/// single-element array when the value is not a JSON object.
+	AsObject,
+}
+
+impl Default for ParamEncoding {
+	fn default() -> Self {
+		ParamEncoding::AsArrayElement
+	}
+}
+
+/// What to do when the per-subscription buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+	/// Apply backpressure: refuse the item until the buffer drains.
+	Block,
+	/// Drop the oldest buffered notification to make room, counting the loss.
+	DropOldest,
+	/// Close the subscription and surface a transport error.
+	CloseSubscription,
+}
+
+impl Default for OverflowPolicy {
+	fn default() -> Self {
+		OverflowPolicy::Block
+	}
 }
 
 /// Subscriber sink.
@@ -51,7 +205,14 @@ impl<T> Subscriber<T> {
 pub struct Sink<T> {
 	sink: pubsub::Sink,
 	id: SubscriptionId,
-	buffered: Option<(String, core::Params)>,
+	buffered: VecDeque<(String, core::Params)>,
+	capacity: usize,
+	policy: OverflowPolicy,
+	dropped: u64,
+	encoding: ParamEncoding,
+	close_notification: Option<String>,
+	close_reason: CloseReason,
+	closed_sent: bool,
 	_data: PhantomData<T>,
 }
 
@@ -61,23 +222,112 @@ impl<T: serde::Serialize> Sink<T> {
 		self.sink.notify(name, self.val_to_params(val))
 	}
 
+	/// Sets the buffer capacity and overflow policy for this sink.
+	///
+	/// A full buffer is handled according to `policy`: `Block` applies
+	/// backpressure (the default, one-slot behaviour), `DropOldest` evicts
+	/// the oldest pending notification, and `CloseSubscription` tears the
+	/// subscription down with a transport error.
+	///
+	/// A `capacity` of `0` would deadlock (`Block`) or count every item as
+	/// dropped (`DropOldest`), so it is clamped up to `1`.
+	pub fn with_capacity(mut self, capacity: usize, policy: OverflowPolicy) -> Self {
+		self.capacity = capacity.max(1);
+		self.policy = policy;
+		self
+	}
+
+	/// Number of notifications dropped so far due to the overflow policy.
+	pub fn dropped(&self) -> u64 {
+		self.dropped
+	}
+
+	/// Sets how notification payloads are encoded into JSON-RPC params.
+	pub fn set_encoding(mut self, encoding: ParamEncoding) -> Self {
+		self.encoding = encoding;
+		self
+	}
+
+	/// Emit a final `SubscriptionClosed` notification under `method` when the
+	/// sink is closed, so clients learn why a subscription went away.
+	///
+	/// This is intentionally opt-in rather than defaulting to a
+	/// `"<name>_closed"` method: the sink does not retain the per-notification
+	/// method name, so there is no name to derive a default from. Without a
+	/// call to `notify_on_close` a closed sink sends nothing; pass the method
+	/// the client expects explicitly. The payload is encoded with the
+	/// configured `ParamEncoding`, matching the shape of data notifications.
+	pub fn notify_on_close(mut self, method: String) -> Self {
+		self.close_notification = Some(method);
+		self
+	}
+
+	/// Sets the reason reported by the close notification on teardown.
+	pub fn set_close_reason(mut self, reason: CloseReason) -> Self {
+		self.close_reason = reason;
+		self
+	}
+
+	/// Forwards every item of `stream` to the subscriber under `name`.
+	///
+	/// Each item is paired with `name` and pumped into this sink; when the
+	/// stream ends (or the sink/downstream fails) the subscription is closed
+	/// so no half-open subscription is left behind. This collapses the usual
+	/// per-subscription forwarding task into a single call.
+	pub fn notify_stream<S>(self, name: String, stream: S) -> impl Future<Item = (), Error = ()>
+	where
+		S: Stream<Item = T, Error = ()>,
+	{
+		let sink = self.sink_map_err(|_| ());
+		// Keep a handle so the subscription is closed even when `forward`
+		// short-circuits on a stream or sink error and doesn't hand the sink back.
+		let close_sink = sink.clone();
+		stream
+			.map(move |item| (name.clone(), item))
+			.forward(sink)
+			.then(move |result| {
+				// Always close, whether forwarding ended by stream exhaustion or
+				// by a sink/downstream error, so no half-open subscription leaks.
+				let mut sink = match result {
+					Ok((_stream, sink)) => sink,
+					Err(()) => close_sink,
+				};
+				futures::future::poll_fn(move || sink.close())
+			})
+	}
+
 	fn val_to_params(&self, val: T) -> core::Params {
+		self.encode_value(to_value(val))
+	}
 
-		core::Params::Array(vec![to_value(val)])
+	/// Encodes an already-serialized value into params per `self.encoding`.
+	fn encode_value(&self, value: core::Value) -> core::Params {
+		match self.encoding {
+			ParamEncoding::AsArrayElement => core::Params::Array(vec![value]),
+			ParamEncoding::AsArray => match value {
+				core::Value::Array(values) => core::Params::Array(values),
+				value => core::Params::Array(vec![value]),
+			},
+			ParamEncoding::AsObject => match value {
+				core::Value::Object(map) => core::Params::Map(map),
+				value => core::Params::Array(vec![value]),
+			},
+		}
 	}
 
 	fn poll(&mut self) -> futures::Poll<(), pubsub::TransportError> {
-		if let Some(item) = self.buffered.take() {
+		while let Some(item) = self.buffered.pop_front() {
 			let result = self.sink.start_send(item)?;
 			if let futures::AsyncSink::NotReady(item) = result {
-				self.buffered = Some(item);
+				self.buffered.push_front(item);
+				break;
 			}
 		}
 
-		if self.buffered.is_some() {
-			Ok(futures::Async::NotReady)
-		} else {
+		if self.buffered.is_empty() {
 			Ok(futures::Async::Ready(()))
+		} else {
+			Ok(futures::Async::NotReady)
 		}
 	}
 }
@@ -87,15 +337,38 @@ impl<T: serde::Serialize> futures::sink::Sink for Sink<T> {
 	type SinkError = pubsub::TransportError;
 
 	fn start_send(&mut self, item: Self::SinkItem) -> futures::StartSend<Self::SinkItem, Self::SinkError> {
-		// Make sure to always try to process the buffered entry.
+		// Make sure to always try to drain the buffered entries first.
 		// Since we're just a proxy to real `Sink` we don't need
 		// to schedule a `Task` wakeup. It will be done downstream.
-		if self.poll()?.is_not_ready() {
-			return Ok(futures::AsyncSink::NotReady(item));
+		self.poll()?;
+
+		if self.buffered.len() >= self.capacity {
+			match self.policy {
+				// Backpressure: refuse the item until the buffer drains.
+				OverflowPolicy::Block => return Ok(futures::AsyncSink::NotReady(item)),
+				// Make room by evicting the oldest pending notification.
+				OverflowPolicy::DropOldest => {
+					self.buffered.pop_front();
+					self.dropped += 1;
+				}
+				// Tear the subscription down and let the error surface.
+				OverflowPolicy::CloseSubscription => {
+					self.close_reason = CloseReason::LimitExceeded;
+					// Best-effort: begin teardown with a single close poll (never
+					// spin inside start_send) and surface the error. The remaining
+					// teardown completes on later `close()` polls; the overflow
+					// item is not delivered.
+					let _ = FuturesSink::close(self)?;
+					return Err(pubsub::TransportError::Other(
+						"subscription buffer capacity exceeded".into(),
+					));
+				}
+			}
 		}
-		let (name, params) = item;
-		let val = self.val_to_params(params);
-		self.buffered = Some((name, val));
+
+		let (name, val) = item;
+		let params = self.val_to_params(val);
+		self.buffered.push_back((name, params));
 		self.poll()?;
 
 		Ok(futures::AsyncSink::Ready)
@@ -108,6 +381,25 @@ impl<T: serde::Serialize> futures::sink::Sink for Sink<T> {
 
 	fn close(&mut self) -> futures::Poll<(), Self::SinkError> {
 		self.poll()?;
+
+		// Queue a final close notification (once) so clients learn the reason.
+		if !self.closed_sent {
+			if let Some(method) = self.close_notification.clone() {
+				let payload = SubscriptionClosed {
+					id: self.id.clone(),
+					reason: self.close_reason.clone(),
+				};
+				let params = self.encode_value(to_value(payload));
+				self.buffered.push_back((method, params));
+			}
+			self.closed_sent = true;
+		}
+
+		// Make sure the close notification is flushed before tearing down.
+		if self.poll()?.is_not_ready() {
+			return Ok(futures::Async::NotReady);
+		}
+
 		self.sink.close()
 	}
 }